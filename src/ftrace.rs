@@ -1,6 +1,13 @@
+extern crate libc;
+
 use std::fs;
 use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time;
 
 pub struct Tracer {
     tracefs: &'static Path,
@@ -73,6 +80,21 @@ impl Tracer {
         }
     }
 
+    // unlike path_write(), does not O_TRUNC the file; some tracefs files
+    // (e.g. synthetic_events) delete existing state on O_TRUNC open, so a
+    // later write would clobber what an earlier one defined
+    fn path_append(&mut self, path: PathBuf, val: &str) {
+        let file = fs::OpenOptions::new().append(true).open(path.as_path());
+        match file {
+            Ok(mut file) => {
+                if let Err(err) = file.write_all(val.as_bytes()) {
+                    self.path_err(err.kind(), path);
+                }
+            }
+            Err(err) => self.path_err(err.kind(), path),
+        }
+    }
+
     fn path_read(&mut self, path: PathBuf) -> String {
         let mut val = String::new();
         match fs::File::open(path.as_path()) {
@@ -102,6 +124,12 @@ impl Tracer {
         self.path_write(path, val);
     }
 
+    // appends instead of truncating; see path_append()
+    pub fn append(&mut self, path: &str, val: &str) {
+        let path = self.tracefs.join(path);
+        self.path_append(path, val);
+    }
+
     pub fn write_bool(&mut self, path: &str, val: bool) {
         self.write(path, bool_to_str(val));
     }
@@ -110,8 +138,129 @@ impl Tracer {
         self.write(path, val.to_string().as_str());
     }
 
+    pub fn set_event_pid(&mut self, pid: i32) {
+        self.write_i32("set_event_pid", pid);
+    }
+
+    pub fn clear_event_pid(&mut self) {
+        self.truncate("set_event_pid");
+    }
+
+    pub fn write_trigger(&mut self, subsystem: &str, event: &str, trigger: &str) {
+        let path = format!("events/{}/{}/trigger", subsystem, event);
+        self.write(&path, trigger);
+    }
+
+    // removes a trigger previously added with write_trigger(); ftrace expects
+    // the exact same trigger spec back, prefixed with "!"
+    pub fn clear_trigger(&mut self, subsystem: &str, event: &str, trigger: &str) {
+        self.write_trigger(subsystem, event, &format!("!{}", trigger));
+    }
+
+    pub fn read_hist(&mut self, subsystem: &str, event: &str) -> String {
+        let path = format!("events/{}/{}/hist", subsystem, event);
+        self.read(&path)
+    }
+
     pub fn read(&mut self, path: &str) -> String {
         let path = self.tracefs.join(path);
         self.path_read(path)
     }
+
+    fn cpu_count(&self) -> usize {
+        let dir = match fs::read_dir(self.tracefs.join("per_cpu")) {
+            Ok(dir) => dir,
+            Err(_) => return 0,
+        };
+
+        dir.filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with("cpu"))
+            .count()
+    }
+
+    // spawns one reader thread per CPU that copies per_cpu/cpuN/trace_pipe_raw
+    // into "<output>.cpuN" for as long as tracing runs, so that events are
+    // never dropped once the ring buffer fills up
+    pub fn start_streaming(&self, output: &Path) -> StreamWriter {
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut cpus = Vec::new();
+        let mut threads = Vec::new();
+
+        for cpu in 0..self.cpu_count() {
+            let in_path = self.tracefs.join(format!("per_cpu/cpu{}/trace_pipe_raw", cpu));
+            let out_path = PathBuf::from(format!("{}.cpu{}", output.to_string_lossy(), cpu));
+            let stop = stop.clone();
+
+            cpus.push(cpu);
+            threads.push(thread::spawn(move || stream_cpu(&in_path, &out_path, &stop)));
+        }
+
+        StreamWriter { cpus, threads, stop }
+    }
+}
+
+// returns true iff both trace_pipe_raw and the output file were opened, i.e.
+// this cpu's events are actually being captured
+fn stream_cpu(in_path: &Path, out_path: &Path, stop: &AtomicBool) -> bool {
+    let input = fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(in_path);
+    let mut input = match input {
+        Ok(file) => file,
+        Err(_) => return false,
+    };
+
+    let mut output = match fs::File::create(out_path) {
+        Ok(file) => file,
+        Err(_) => return false,
+    };
+
+    let mut buf = [0u8; 128 * 1024];
+    loop {
+        match input.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => {
+                let _ = output.write_all(&buf[..n]);
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                // nothing buffered yet; stop once tracing has been disabled
+                // and there is nothing left to drain
+                if stop.load(Ordering::Relaxed) {
+                    break;
+                }
+                thread::sleep(time::Duration::from_millis(20));
+            }
+            Err(_) => break,
+        }
+    }
+
+    true
+}
+
+// joins the per-CPU reader threads spawned by Tracer::start_streaming()
+pub struct StreamWriter {
+    cpus: Vec<usize>,
+    threads: Vec<thread::JoinHandle<bool>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl StreamWriter {
+    pub fn cpu_count(&self) -> usize {
+        self.cpus.len()
+    }
+
+    // stops all per-CPU reader threads and returns the CPUs whose
+    // trace_pipe_raw or output file never opened, so the caller can warn
+    // about events that were silently not captured
+    pub fn stop(self) -> Vec<usize> {
+        self.stop.store(true, Ordering::Relaxed);
+        self.cpus.into_iter()
+            .zip(self.threads)
+            .filter_map(|(cpu, thread)| match thread.join() {
+                Ok(true) => None,
+                _ => Some(cpu),
+            })
+            .collect()
+    }
 }
@@ -1,17 +1,36 @@
+extern crate libc;
+
 mod ftrace;
+mod procs;
 mod sleep;
 
-use ftrace::Tracer;
+use ftrace::{StreamWriter, Tracer};
 use std::collections::{HashMap, HashSet};
 use std::env;
+use std::ffi::CString;
 use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::time;
 
 struct Config {
     output: PathBuf,
     timeout: u32,
     enabled_categories: Vec<usize>,
     explicit: bool,
+    command: Vec<String>,
+    pid: Option<i32>,
+    streaming: bool,
+    ftrace_globs: Vec<String>,
+    thresh: Option<u32>,
+    hist: bool,
+}
+
+impl Config {
+    // -g implies the function_graph capture mode, which is mutually
+    // exclusive with the event-category path
+    fn function_graph(&self) -> bool {
+        !self.ftrace_globs.is_empty()
+    }
 }
 
 struct Event {
@@ -20,10 +39,22 @@ struct Event {
     required: bool,
 }
 
+// describes the begin/end event pair for an in-kernel hist trigger latency
+// histogram (see set_hist_triggers()); `keys` must name fields common to both
+// events so the end event's histogram entry can match the begin event's
+struct HistPair {
+    subsystem: &'static str,
+    begin: &'static str,
+    end: &'static str,
+    keys: &'static [&'static str],
+    synthetic_event: &'static str,
+}
+
 struct Category {
     name: &'static str,
     description: &'static str,
     events: &'static [Event],
+    hist: Option<HistPair>,
 }
 
 static CATEGORIES: [Category; 10] = [
@@ -69,6 +100,7 @@ static CATEGORIES: [Category; 10] = [
                 required: false,
             },
         ],
+        hist: None,
     },
     Category {
         name: "freq",
@@ -115,6 +147,7 @@ static CATEGORIES: [Category; 10] = [
                 required: false,
             },
         ],
+        hist: None,
     },
     Category {
         name: "idle",
@@ -126,6 +159,7 @@ static CATEGORIES: [Category; 10] = [
                 required: true,
             },
         ],
+        hist: None,
     },
     Category {
         name: "irq",
@@ -137,6 +171,7 @@ static CATEGORIES: [Category; 10] = [
                 required: true,
             },
         ],
+        hist: None,
     },
     Category {
         name: "drm",
@@ -148,6 +183,7 @@ static CATEGORIES: [Category; 10] = [
                 required: true,
             },
         ],
+        hist: None,
     },
     Category {
         name: "fence",
@@ -164,6 +200,13 @@ static CATEGORIES: [Category; 10] = [
                 required: true,
             },
         ],
+        hist: Some(HistPair {
+            subsystem: "dma_fence",
+            begin: "dma_fence_emit",
+            end: "dma_fence_signaled",
+            keys: &["context", "seqno"],
+            synthetic_event: "fence_latency",
+        }),
     },
     Category {
         name: "virtio-gpu",
@@ -175,6 +218,7 @@ static CATEGORIES: [Category; 10] = [
                 required: true,
             },
         ],
+        hist: None,
     },
     Category {
         name: "i915",
@@ -241,6 +285,13 @@ static CATEGORIES: [Category; 10] = [
                 required: true,
             },
         ],
+        hist: Some(HistPair {
+            subsystem: "i915",
+            begin: "i915_request_wait_begin",
+            end: "i915_request_wait_end",
+            keys: &["dev", "seqno"],
+            synthetic_event: "gpu_latency",
+        }),
     },
     Category {
         name: "kvm",
@@ -277,6 +328,7 @@ static CATEGORIES: [Category; 10] = [
                 required: true,
             },
         ],
+        hist: None,
     },
     Category {
         name: "syscalls",
@@ -288,11 +340,12 @@ static CATEGORIES: [Category; 10] = [
                 required: true,
             },
         ],
+        hist: None,
     },
 ];
 
 fn usage() {
-    println!("Usage: {} [options] [category1] [category2]...",
+    println!("Usage: {} [options] [category1] [category2]... [-- command [args]...]",
              env::args().nth(0).unwrap());
 
     println!();
@@ -300,6 +353,17 @@ fn usage() {
     println!("  -h            Print this message.");
     println!("  -o <filename> Save the trace to <filename>.");
     println!("  -t <timeout>  Trace for <timeout> seconds.");
+    println!("  -p <pid>      Trace only <pid> and its descendants.");
+    println!("                Applies to events only; -g output is not scoped.");
+    println!("  -s            Stream per-CPU trace_pipe_raw to disk continuously,");
+    println!("                instead of dumping the trace buffer once at the end.");
+    println!("  -g <glob>     Trace functions matching <glob> with function_graph instead");
+    println!("                of recording events (repeatable).");
+    println!("  --thresh <us> Only record function_graph calls slower than <us> us.");
+    println!("  --hist        Record latency histograms instead of raw events, for");
+    println!("                categories that support it (e.g. fence, i915).");
+    println!("  -- <command>  Trace <command> instead, for as long as it runs");
+    println!("                (or until <timeout> seconds, whichever is first).");
 
     println!();
     println!("Available categories are:");
@@ -316,6 +380,12 @@ fn parse_args() -> Config {
         timeout: 5,
         enabled_categories: Vec::new(),
         explicit: false,
+        command: Vec::new(),
+        pid: None,
+        streaming: false,
+        ftrace_globs: Vec::new(),
+        thresh: None,
+        hist: false,
     };
 
     let mut known_categories = HashMap::new();
@@ -325,9 +395,39 @@ fn parse_args() -> Config {
 
     let mut args = env::args().skip(1);
     let mut enabled_categories = HashSet::new();
+    let mut saw_command_sep = false;
     while let Some(arg) = args.next() {
-        if arg == "-h" {
+        if arg == "--" {
+            saw_command_sep = true;
+            config.command.extend(args);
+            break;
+        } else if arg == "-h" {
             usage();
+        } else if arg == "-s" {
+            config.streaming = true;
+        } else if arg == "--hist" {
+            config.hist = true;
+        } else if arg == "-g" {
+            match args.next() {
+                Some(next) => config.ftrace_globs.push(next),
+                None => {
+                    println!("glob is missing");
+                    usage();
+                }
+            }
+        } else if arg == "--thresh" {
+            let mut thresh = None;
+            if let Some(next) = args.next() {
+                thresh = next.parse().ok();
+            }
+
+            match thresh {
+                Some(thresh) => config.thresh = Some(thresh),
+                None => {
+                    println!("failed to parse threshold");
+                    usage();
+                }
+            }
         } else if arg == "-o" {
             match args.next() {
                 Some(next) => config.output = PathBuf::from(next),
@@ -349,6 +449,19 @@ fn parse_args() -> Config {
                     usage();
                 }
             }
+        } else if arg == "-p" {
+            let mut pid = None;
+            if let Some(next) = args.next() {
+                pid = next.parse().ok();
+            }
+
+            match pid {
+                Some(pid) => config.pid = Some(pid),
+                None => {
+                    println!("failed to parse pid");
+                    usage();
+                }
+            }
         } else {
             match known_categories.get(arg.as_str()) {
                 Some(index) => {
@@ -370,6 +483,26 @@ fn parse_args() -> Config {
         config.explicit = true;
     }
 
+    if saw_command_sep && config.command.is_empty() {
+        println!("command is missing after --");
+        usage();
+    }
+
+    // trace_command() always scopes tracing to the launched command's own
+    // pid, so an explicit -p would be silently discarded
+    if !config.command.is_empty() && config.pid.is_some() {
+        println!("-p is not compatible with -- <command>");
+        usage();
+    }
+
+    // -g switches to function_graph mode, which traces kernel functions
+    // instead of events, so explicit categories and --hist would otherwise
+    // be silently dropped
+    if config.function_graph() && (config.explicit || config.hist) {
+        println!("-g is not compatible with categories or --hist");
+        usage();
+    }
+
     config
 }
 
@@ -408,7 +541,7 @@ fn set_trace_clock(tracer: &mut Tracer) {
     }
 }
 
-fn set_options(tracer: &mut Tracer) {
+fn set_options(tracer: &mut Tracer, config: &Config) {
     // clear trace
     tracer.write_bool("tracing_on", false);
     tracer.truncate("trace");
@@ -420,11 +553,65 @@ fn set_options(tracer: &mut Tracer) {
         tracer.write_bool("options/print-tgid", true);
     }
 
+    // older kernels lack set_event_pid / event-fork; only follow forks when
+    // both exist (set_event_pid() itself skips pid scoping without the former)
+    if tracer.test("set_event_pid") && tracer.test("options/event-fork") {
+        tracer.write_bool("options/event-fork", true);
+    }
+
     tracer.write_i32("buffer_size_kb", 32 * 1024);
+
+    if config.function_graph() {
+        set_function_graph_options(tracer, &config.ftrace_globs, config.thresh);
+    } else {
+        tracer.write("current_tracer", "nop");
+        tracer.truncate("set_ftrace_filter");
+    }
+
+    set_trace_clock(tracer);
+}
+
+// function_graph mode is a latency profiler mutually exclusive with the
+// event-category path: it traces kernel function calls matching `globs`
+// instead of enabling any events
+fn set_function_graph_options(tracer: &mut Tracer, globs: &[String], thresh: Option<u32>) {
+    let filter = globs.join("\n");
+    tracer.write("set_ftrace_filter", &filter);
+    tracer.write("set_graph_function", &filter);
+
+    tracer.write("current_tracer", "function_graph");
+
+    if tracer.test("options/funcgraph-abstime") {
+        tracer.write_bool("options/funcgraph-abstime", true);
+    }
+    if tracer.test("options/funcgraph-proc") {
+        tracer.write_bool("options/funcgraph-proc", true);
+    }
+
+    // tracing_thresh is backed by tracing_open_generic, which has no O_TRUNC
+    // handling; only an actual write of "0" resets it
+    tracer.write_i32("tracing_thresh", thresh.unwrap_or(0) as i32);
+}
+
+fn clear_function_graph_options(tracer: &mut Tracer) {
     tracer.write("current_tracer", "nop");
     tracer.truncate("set_ftrace_filter");
+    tracer.truncate("set_graph_function");
+    tracer.write_i32("tracing_thresh", 0);
+}
 
-    set_trace_clock(tracer);
+fn set_event_pid(tracer: &mut Tracer, pid: i32) {
+    if tracer.test("set_event_pid") {
+        tracer.set_event_pid(pid);
+    } else {
+        println!("set_event_pid is not supported; tracing all processes");
+    }
+}
+
+fn clear_event_pid(tracer: &mut Tracer) {
+    if tracer.test("set_event_pid") {
+        tracer.clear_event_pid();
+    }
 }
 
 fn collect_events(tracer: &Tracer, categories: &Vec<usize>, explicit: bool) -> Vec<String> {
@@ -476,12 +663,201 @@ fn set_events(tracer: &mut Tracer, paths: &Vec<String>, enable: bool) {
     }
 }
 
-fn trace(tracer: &mut Tracer, timeout: u32) {
+// a hist trigger session programmed by set_hist_triggers(); keeps the exact
+// trigger strings around so clear_hist_triggers() can negate them later
+struct HistSession {
+    hist: &'static HistPair,
+    begin_trigger: String,
+    end_trigger: String,
+    synth_trigger: String,
+}
+
+fn hist_supported(tracer: &Tracer, hist: &HistPair) -> bool {
+    tracer.test("synthetic_events")
+        && tracer.test(&format!("events/{}/{}/hist", hist.subsystem, hist.begin))
+        && tracer.test(&format!("events/{}/{}/hist", hist.subsystem, hist.end))
+}
+
+fn set_hist_triggers(tracer: &mut Tracer, hist: &'static HistPair) -> HistSession {
+    let keys = hist.keys.join(",");
+
+    // synthetic_events is a dyn-event file: opening it with O_TRUNC deletes
+    // every synthetic event already defined, not just the one being
+    // replaced, so multiple hist-enabled categories must append instead
+    tracer.append("synthetic_events", &format!("{} u64 lat\n", hist.synthetic_event));
+
+    let begin_trigger = format!("hist:keys={}:ts0=common_timestamp.usecs", keys);
+    tracer.write_trigger(hist.subsystem, hist.begin, &begin_trigger);
+
+    let end_trigger = format!(
+        "hist:keys={}:lat=common_timestamp.usecs-$ts0:onmatch({}.{}).{}(lat)",
+        keys, hist.subsystem, hist.begin, hist.synthetic_event
+    );
+    tracer.write_trigger(hist.subsystem, hist.end, &end_trigger);
+
+    let synth_trigger = "hist:keys=lat.log2".to_string();
+    tracer.write_trigger("synthetic", hist.synthetic_event, &synth_trigger);
+
+    HistSession { hist, begin_trigger, end_trigger, synth_trigger }
+}
+
+fn clear_hist_triggers(tracer: &mut Tracer, session: &HistSession) {
+    let hist = session.hist;
+    tracer.clear_trigger("synthetic", hist.synthetic_event, &session.synth_trigger);
+    tracer.clear_trigger(hist.subsystem, hist.end, &session.end_trigger);
+    tracer.clear_trigger(hist.subsystem, hist.begin, &session.begin_trigger);
+    tracer.append("synthetic_events", &format!("!{} u64 lat\n", hist.synthetic_event));
+}
+
+fn read_hist_sessions(tracer: &mut Tracer, sessions: &[HistSession]) -> String {
+    let mut buf = String::new();
+    for session in sessions {
+        buf.push_str(&format!("# {}\n", session.hist.synthetic_event));
+        buf.push_str(&tracer.read_hist("synthetic", session.hist.synthetic_event));
+        buf.push('\n');
+    }
+
+    buf
+}
+
+// warns about per-CPU trace_pipe_raw readers that never opened, since that
+// silently drops a cpu's events instead of the ring buffer just overwriting
+// old ones like the non-streaming path would
+fn report_streaming_failures(writer: StreamWriter) {
+    let total = writer.cpu_count();
+    let failed = writer.stop();
+
+    if total == 0 {
+        println!("warning: no per-CPU trace_pipe_raw files found; streaming captured nothing");
+    } else if failed.len() == total {
+        println!("warning: all {} per-cpu trace readers failed to open; streaming captured nothing", total);
+    } else {
+        for cpu in &failed {
+            println!("warning: failed to open trace_pipe_raw for cpu{}; its events were not captured", cpu);
+        }
+    }
+}
+
+fn trace(tracer: &mut Tracer, timeout: u32, output: &Path, streaming: bool) {
+    let writer = if streaming {
+        Some(tracer.start_streaming(output))
+    } else {
+        None
+    };
+
     tracer.write_bool("tracing_on", true);
     if !tracer.has_err() {
         sleep::sleep(timeout);
     }
     tracer.write_bool("tracing_on", false);
+
+    if let Some(writer) = writer {
+        report_streaming_failures(writer);
+    }
+}
+
+// forks `command`, stopping the child with SIGSTOP right after fork() so the
+// caller can enable tracing before resume_command() lets it exec and start
+// doing real work
+fn spawn_command(command: &[String]) -> libc::pid_t {
+    let args: Vec<CString> = command.iter()
+        .map(|arg| CString::new(arg.as_str()).unwrap())
+        .collect();
+    let mut argv: Vec<*const libc::c_char> = args.iter().map(|arg| arg.as_ptr()).collect();
+    argv.push(std::ptr::null());
+
+    let pid = unsafe { libc::fork() };
+    if pid < 0 {
+        return pid;
+    }
+    if pid == 0 {
+        unsafe {
+            libc::raise(libc::SIGSTOP);
+            libc::execvp(argv[0], argv.as_ptr());
+            libc::_exit(127);
+        }
+    }
+
+    pid
+}
+
+// blocks until `pid` has stopped itself with SIGSTOP right after fork()
+fn wait_for_stop(pid: libc::pid_t) {
+    let mut status: libc::c_int = 0;
+    unsafe {
+        libc::waitpid(pid, &mut status, libc::WUNTRACED);
+    }
+}
+
+fn resume_command(pid: libc::pid_t) {
+    unsafe {
+        libc::kill(pid, libc::SIGCONT);
+    }
+}
+
+// waits for `pid` to exit, or for `timeout` seconds to elapse, or for SIGINT,
+// whichever comes first; returns true iff the child has already been reaped
+fn wait_command(pid: libc::pid_t, timeout: u32) -> bool {
+    let limit = time::Duration::from_secs(timeout.into());
+    let poll = time::Duration::from_millis(100);
+    let signal = sleep::Signal::new();
+    let start = time::Instant::now();
+
+    loop {
+        let mut status: libc::c_int = 0;
+        if unsafe { libc::waitpid(pid, &mut status, libc::WNOHANG) } == pid {
+            return true;
+        }
+
+        if signal.interrupted() {
+            return false;
+        }
+
+        let elapsed = start.elapsed();
+        if elapsed >= limit {
+            return false;
+        }
+
+        signal.wait(std::cmp::min(poll, limit - elapsed));
+    }
+}
+
+fn trace_command(tracer: &mut Tracer, command: &[String], timeout: u32, output: &Path, streaming: bool) {
+    let pid = spawn_command(command);
+    if pid < 0 {
+        println!("failed to fork to launch {}", command.join(" "));
+        return;
+    }
+
+    // the child stopped itself right after fork(); hold it there until
+    // tracing is enabled so its exec() and early-lifetime events are captured
+    wait_for_stop(pid);
+
+    // scope the trace to the launched process tree instead of the whole system;
+    // set_event_pid filters events only, so function_graph output is not scoped
+    set_event_pid(tracer, pid);
+
+    let writer = if streaming {
+        Some(tracer.start_streaming(output))
+    } else {
+        None
+    };
+
+    tracer.write_bool("tracing_on", true);
+    resume_command(pid);
+
+    if !tracer.has_err() && !wait_command(pid, timeout) {
+        // timed out or interrupted before the command exited on its own
+        unsafe {
+            libc::kill(pid, libc::SIGINT);
+            libc::waitpid(pid, std::ptr::null_mut(), 0);
+        }
+    }
+    tracer.write_bool("tracing_on", false);
+
+    if let Some(writer) = writer {
+        report_streaming_failures(writer);
+    }
 }
 
 fn dump_trace(tracer: &mut Tracer, output: &Path) {
@@ -492,6 +868,18 @@ fn dump_trace(tracer: &mut Tracer, output: &Path) {
     tracer.truncate("trace");
 }
 
+fn procs_sidecar_path(output: &Path) -> PathBuf {
+    let mut name = output.as_os_str().to_os_string();
+    name.push(".procs");
+    PathBuf::from(name)
+}
+
+fn hist_sidecar_path(output: &Path) -> PathBuf {
+    let mut name = output.as_os_str().to_os_string();
+    name.push(".hist");
+    PathBuf::from(name)
+}
+
 fn check_error(tracer: &Tracer, msg: &str) {
     if !tracer.has_err() {
         return;
@@ -511,22 +899,83 @@ fn main() {
     check_error(&tracer, "failed to set tracefs");
 
     println!("setting options...");
-    set_options(&mut tracer);
+    set_options(&mut tracer, &config);
     check_error(&tracer, "failed to set options");
 
-    println!("setting events...");
-    let event_paths = collect_events(&tracer, &config.enabled_categories, config.explicit);
+    let mut hist_sessions = Vec::new();
+    let event_paths = if config.function_graph() {
+        Vec::new()
+    } else {
+        println!("setting events...");
+
+        let mut event_categories = Vec::new();
+        for &index in &config.enabled_categories {
+            let cat = &CATEGORIES[index];
+
+            match cat.hist.as_ref() {
+                Some(hist) if config.hist && hist_supported(&tracer, hist) => {
+                    hist_sessions.push(set_hist_triggers(&mut tracer, hist));
+                }
+                Some(_) if config.hist => {
+                    println!("hist triggers unsupported for category {}; recording raw events instead", cat.name);
+                    event_categories.push(index);
+                }
+                _ => event_categories.push(index),
+            }
+        }
+
+        collect_events(&tracer, &event_categories, config.explicit)
+    };
     set_events(&mut tracer, &event_paths, true);
     check_error(&tracer, "failed to set events");
 
-    println!("tracing for {} seconds...", config.timeout);
-    trace(&mut tracer, config.timeout);
+    let mut procs = procs::snapshot();
+
+    if config.command.is_empty() {
+        if let Some(pid) = config.pid {
+            set_event_pid(&mut tracer, pid);
+        }
+
+        println!("tracing for {} seconds...", config.timeout);
+        trace(&mut tracer, config.timeout, &config.output, config.streaming);
+    } else {
+        println!("tracing {}...", config.command.join(" "));
+        trace_command(&mut tracer, &config.command, config.timeout, &config.output, config.streaming);
+    }
     check_error(&tracer, "failed to enable tracing");
 
-    println!("saving the trace to {}...", config.output.to_string_lossy());
-    dump_trace(&mut tracer, &config.output);
-    check_error(&tracer, "failed to save the trace");
+    // tasks that exited or were spawned mid-trace are only resolvable from
+    // one of the two snapshots, so keep both
+    for (tid, task) in procs::snapshot() {
+        procs.entry(tid).or_insert(task);
+    }
+
+    if config.streaming {
+        println!("saved per-cpu traces to {}.cpu*", config.output.to_string_lossy());
+    } else {
+        println!("saving the trace to {}...", config.output.to_string_lossy());
+        dump_trace(&mut tracer, &config.output);
+        check_error(&tracer, "failed to save the trace");
+    }
+
+    let procs_output = procs_sidecar_path(&config.output);
+    println!("saving process metadata to {}...", procs_output.to_string_lossy());
+    procs::write(&procs, &procs_output);
+
+    if !hist_sessions.is_empty() {
+        let hist_output = hist_sidecar_path(&config.output);
+        println!("saving latency histograms to {}...", hist_output.to_string_lossy());
+        let buf = read_hist_sessions(&mut tracer, &hist_sessions);
+        let _ = std::fs::write(&hist_output, buf);
+    }
 
     // clean up
     set_events(&mut tracer, &event_paths, false);
+    clear_event_pid(&mut tracer);
+    if config.function_graph() {
+        clear_function_graph_options(&mut tracer);
+    }
+    for session in &hist_sessions {
+        clear_hist_triggers(&mut tracer, session);
+    }
 }
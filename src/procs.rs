@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+pub struct Task {
+    pub tgid: i32,
+    pub comm: String,
+    pub cmdline: String,
+}
+
+pub type Snapshot = HashMap<i32, Task>;
+
+fn is_enoent(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::NotFound
+}
+
+// /proc/<pid>/stat starts with "<pid> (<comm>) <state> ...", and comm itself
+// may contain spaces or parens, so match the outermost parens rather than
+// splitting on whitespace
+fn read_stat_comm(pid: i32) -> Option<String> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    let start = stat.find('(')?;
+    let end = stat.rfind(')')?;
+    if end <= start {
+        return None;
+    }
+
+    Some(stat[start + 1..end].to_string())
+}
+
+fn read_cmdline(pid: i32) -> String {
+    match fs::read(format!("/proc/{}/cmdline", pid)) {
+        // cmdline is NUL-separated and not guaranteed to be UTF-8
+        Ok(buf) => String::from_utf8_lossy(&buf).replace('\0', " ").trim().to_string(),
+        Err(_) => String::new(),
+    }
+}
+
+fn read_task_comm(pid: i32, tid: i32) -> Option<String> {
+    match fs::read(format!("/proc/{}/task/{}/comm", pid, tid)) {
+        Ok(buf) => Some(String::from_utf8_lossy(&buf).trim_end().to_string()),
+        // the task may have exited between being listed and being read
+        Err(ref err) if is_enoent(err) => None,
+        Err(_) => None,
+    }
+}
+
+// walks /proc once and returns a pid/tid -> {tgid, comm, cmdline} map; callers
+// are expected to snapshot at both trace start and end and merge the two, so
+// that tasks that exit or get spawned mid-trace are still resolvable
+pub fn snapshot() -> Snapshot {
+    let mut tasks = Snapshot::new();
+
+    let proc_dir = match fs::read_dir("/proc") {
+        Ok(dir) => dir,
+        Err(_) => return tasks,
+    };
+
+    for entry in proc_dir.filter_map(|entry| entry.ok()) {
+        let pid: i32 = match entry.file_name().to_str().and_then(|s| s.parse().ok()) {
+            Some(pid) => pid,
+            // not a pid directory, e.g. /proc/self or /proc/net
+            None => continue,
+        };
+
+        let cmdline = read_cmdline(pid);
+        let comm = read_stat_comm(pid).unwrap_or_default();
+        tasks.insert(pid, Task { tgid: pid, comm, cmdline: cmdline.clone() });
+
+        let task_dir = match fs::read_dir(format!("/proc/{}/task", pid)) {
+            Ok(dir) => dir,
+            // the process exited while we were walking /proc
+            Err(ref err) if is_enoent(err) => continue,
+            Err(_) => continue,
+        };
+
+        for task in task_dir.filter_map(|task| task.ok()) {
+            let tid: i32 = match task.file_name().to_str().and_then(|s| s.parse().ok()) {
+                Some(tid) => tid,
+                None => continue,
+            };
+
+            if tid == pid {
+                continue;
+            }
+
+            if let Some(comm) = read_task_comm(pid, tid) {
+                tasks.insert(tid, Task { tgid: pid, comm, cmdline: cmdline.clone() });
+            }
+        }
+    }
+
+    tasks
+}
+
+pub fn write(snapshot: &Snapshot, path: &Path) {
+    let mut tids: Vec<&i32> = snapshot.keys().collect();
+    tids.sort();
+
+    let mut buf = String::new();
+    for tid in tids {
+        let task = &snapshot[tid];
+        buf.push_str(&format!("{}\t{}\t{}\t{}\n", tid, task.tgid, task.comm, task.cmdline));
+    }
+
+    let _ = fs::write(path, buf);
+}
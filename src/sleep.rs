@@ -1,31 +1,65 @@
 extern crate libc;
 
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Condvar, Mutex};
 use std::time;
 
 static mut SIGNAL: Option<Arc<(Mutex<()>, Condvar)>> = None;
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
 
 unsafe extern "C" fn signal_handler(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
     let signal = SIGNAL.as_ref().unwrap();
     let _guard = signal.0.lock().unwrap();
     signal.1.notify_one();
 }
 
-pub fn sleep(secs : u32) {
-    let dur = time::Duration::from_secs(secs.into());
+/// Installs a SIGINT handler for the lifetime of the value, letting callers
+/// wait with a timeout that is interrupted as soon as Ctrl-C is pressed.
+pub struct Signal {
+    inner: Arc<(Mutex<()>, Condvar)>,
+}
+
+impl Signal {
+    pub fn new() -> Signal {
+        let inner = Arc::new((Mutex::new(()), Condvar::new()));
 
-    let signal = Arc::new((Mutex::new(()), Condvar::new()));
-    let guard = signal.0.lock().unwrap();
+        INTERRUPTED.store(false, Ordering::SeqCst);
+        unsafe {
+            SIGNAL = Some(inner.clone());
+            libc::signal(libc::SIGINT, signal_handler as usize);
+        }
+
+        Signal { inner }
+    }
 
-    unsafe {
-        SIGNAL = Some(signal.clone());
-        libc::signal(libc::SIGINT, signal_handler as usize);
+    /// Waits until `dur` elapses or SIGINT is received, whichever is first.
+    pub fn wait(&self, dur: time::Duration) {
+        let guard = self.inner.0.lock().unwrap();
+        // the handler sets INTERRUPTED before taking the lock, so a SIGINT
+        // that arrived before the lock was taken is seen here
+        if self.interrupted() {
+            return;
+        }
+        let _ = self.inner.1.wait_timeout(guard, dur);
     }
 
-    let _ = signal.1.wait_timeout(guard, dur);
+    /// Returns true once SIGINT has been received since this Signal was created.
+    pub fn interrupted(&self) -> bool {
+        INTERRUPTED.load(Ordering::SeqCst)
+    }
+}
 
-    unsafe {
-        libc::signal(libc::SIGINT, libc::SIG_DFL);
-        SIGNAL = None;
+impl Drop for Signal {
+    fn drop(&mut self) {
+        unsafe {
+            libc::signal(libc::SIGINT, libc::SIG_DFL);
+            SIGNAL = None;
+        }
     }
 }
+
+pub fn sleep(secs: u32) {
+    let dur = time::Duration::from_secs(secs.into());
+    Signal::new().wait(dur);
+}